@@ -0,0 +1,140 @@
+// Observation preprocessing and a convolutional policy for pixel-based Gym
+// environments (Atari games such as Breakout or Pong), following the
+// standard DQN/PPO preprocessing pipeline: convert the raw RGB frame to
+// grayscale, resize it to 84x84, scale it to [0, 1], and stack the last
+// `NSTACK` frames so the policy can perceive motion.
+use crate::vec_gym_env::{ActionSpace, VecGymEnv};
+use cpython::PyResult;
+use tch::{nn, Kind, Tensor};
+
+pub const FRAME_SIZE: i64 = 84;
+pub const NSTACK: i64 = 4;
+
+// Converts a batch of raw `[n, ..obs_shape]` frames (as returned by the
+// underlying Gym env, `obs_shape` typically `[210, 160, 3]`) into a batch of
+// `[n, FRAME_SIZE, FRAME_SIZE]` grayscale float frames in `[0, 1]`.
+fn preprocess(raw: &Tensor, obs_shape: &[i64]) -> Tensor {
+    let nprocs = raw.size()[0];
+    let mut shape = vec![nprocs];
+    shape.extend_from_slice(obs_shape);
+    let frames = raw.view(shape.as_slice()).to_kind(Kind::Float) / 255.;
+    let luminance = Tensor::of_slice(&[0.299, 0.587, 0.114]).view([1, 1, 1, 3]);
+    let gray = (frames * luminance).sum2(&[3], false).unsqueeze(1);
+    gray.upsample_bilinear2d(&[FRAME_SIZE, FRAME_SIZE], false, None, None)
+        .squeeze1(1)
+}
+
+/// Wraps a `VecGymEnv` over a pixel-observation Gym env, preprocessing each
+/// frame and maintaining a rolling stack of the last `NSTACK` of them per
+/// sub-environment.
+pub struct AtariVecEnv<'a> {
+    env: VecGymEnv<'a>,
+    obs_shape: Vec<i64>,
+    stacks: Vec<Tensor>,
+}
+
+fn empty_stack() -> Tensor {
+    Tensor::zeros(&[NSTACK, FRAME_SIZE, FRAME_SIZE], tch::kind::FLOAT_CPU)
+}
+
+impl<'a> AtariVecEnv<'a> {
+    pub fn new(env: VecGymEnv<'a>) -> AtariVecEnv<'a> {
+        let obs_shape = env.observation_space().to_vec();
+        let stacks = (0..env.nprocesses()).map(|_| empty_stack()).collect();
+        AtariVecEnv {
+            env,
+            obs_shape,
+            stacks,
+        }
+    }
+
+    pub fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    pub fn observation_space(&self) -> Vec<i64> {
+        vec![NSTACK, FRAME_SIZE, FRAME_SIZE]
+    }
+
+    // Pushes one new preprocessed frame per sub-environment onto its stack,
+    // dropping the oldest frame, and returns the resulting `[nprocs, NSTACK,
+    // FRAME_SIZE, FRAME_SIZE]` batch.
+    fn push_frames(&mut self, frames: &Tensor) -> Tensor {
+        for (i, stack) in self.stacks.iter_mut().enumerate() {
+            let frame = frames.get(i as i64).unsqueeze(0);
+            let rest = stack.narrow(0, 1, NSTACK - 1);
+            *stack = Tensor::cat(&[rest, frame], 0);
+        }
+        Tensor::stack(&self.stacks, 0)
+    }
+
+    pub fn reset(&mut self) -> PyResult<Tensor> {
+        let raw = self.env.reset()?;
+        let frames = preprocess(&raw, &self.obs_shape);
+        for stack in self.stacks.iter_mut() {
+            *stack = empty_stack();
+        }
+        Ok(self.push_frames(&frames))
+    }
+
+    pub fn step(&mut self, actions: &Tensor) -> PyResult<(Tensor, Tensor, Tensor)> {
+        let (raw, rewards, is_done) = self.env.step(actions)?;
+        let frames = preprocess(&raw, &self.obs_shape);
+        // A done slot's frame is already the first one of the next episode
+        // (see `VecGymEnv::step`), so clear its stack before pushing it.
+        let done = Vec::<f64>::from(&is_done);
+        for (i, d) in done.iter().enumerate() {
+            if *d > 0.5 {
+                self.stacks[i] = empty_stack();
+            }
+        }
+        let obs = self.push_frames(&frames);
+        Ok((obs, rewards, is_done))
+    }
+}
+
+/// Convolutional actor-critic matching the architecture used by the
+/// original Atari DQN paper and carried over into most Atari PPO baselines:
+/// three conv layers (32x8x8 stride 4, 64x4x4 stride 2, 64x3x3 stride 1)
+/// followed by a fully-connected trunk and linear actor/critic heads.
+pub struct CnnActorCritic {
+    conv1: nn::Conv2D,
+    conv2: nn::Conv2D,
+    conv3: nn::Conv2D,
+    linear: nn::Linear,
+    actor: nn::Linear,
+    critic: nn::Linear,
+}
+
+pub fn nn_cnn(p: &nn::Path, nstack: i64, nact: i64) -> CnnActorCritic {
+    let stride = |s| nn::ConvConfig {
+        stride: s,
+        ..Default::default()
+    };
+    CnnActorCritic {
+        conv1: nn::Conv2D::new(p / "conv1", nstack, 32, 8, stride(4)),
+        conv2: nn::Conv2D::new(p / "conv2", 32, 64, 4, stride(2)),
+        conv3: nn::Conv2D::new(p / "conv3", 64, 64, 3, stride(1)),
+        linear: nn::Linear::new(p / "lin1", 64 * 7 * 7, 512, Default::default()),
+        actor: nn::Linear::new(p / "actor", 512, nact, Default::default()),
+        critic: nn::Linear::new(p / "critic", 512, 1, Default::default()),
+    }
+}
+
+impl CnnActorCritic {
+    // Returns the (action logits, state value) pair for a batch of stacked
+    // `[batch, NSTACK, FRAME_SIZE, FRAME_SIZE]` frames.
+    pub fn forward(&self, obs: &Tensor) -> (Tensor, Tensor) {
+        let xs = obs
+            .apply(&self.conv1)
+            .relu()
+            .apply(&self.conv2)
+            .relu()
+            .apply(&self.conv3)
+            .relu()
+            .flat_view()
+            .apply(&self.linear)
+            .relu();
+        (xs.apply(&self.actor), xs.apply(&self.critic))
+    }
+}
@@ -1,7 +1,40 @@
-// Policy gradient example.
+// Proximal Policy Optimization (PPO) example with a recurrent policy.
 // This uses OpenAI Gym environment through rust-cpython.
-// For now this uses the CartPole-v0 environment and hardcodes the number
-// of observations (4) and actions (2).
+// The environment id is passed in at startup and the observation/action
+// spaces are discovered at runtime from the Python `gym.Env` object, so the
+// same binary can train on any Gym env exposing a `Box` observation space
+// and a `Discrete` action space (CartPole, Pong, Breakout, ...) - there is
+// no continuous-action policy here, so envs like Pendulum that expose a
+// continuous action space will panic on startup.
+//
+// Rollouts are collected from `NPROCS` copies of the environment at once via
+// `VecGymEnv`, so a single `apply(&model)` call produces actions for the
+// whole batch. This both keeps the model's inference batched and decorrelates
+// the trajectories making up a training batch.
+//
+// Advantages are estimated via GAE. Each rollout is then reused for
+// `PPO_EPOCHS` passes, with the policy update clipped so that it cannot move
+// the action probabilities too far from the ones that generated the rollout -
+// this is what lets the same batch of experience be trained on multiple
+// times without the policy diverging.
+//
+// Observations and rewards are run through `VecNormalize`, which keeps a
+// running mean/variance so the example converges across environments with
+// very different observation and reward scales.
+//
+// The default policy is an LSTM rather than a plain MLP, which lets it solve
+// partially observable variants of these tasks (e.g. with velocity
+// components masked out of the observation) that require remembering past
+// observations. The hidden state is carried across timesteps during
+// rollout collection and is reset to zero whenever an episode ends; during
+// the PPO update, trajectories are replayed through the LSTM in sequence,
+// `CHUNK_LEN` steps at a time (truncated backprop-through-time), seeded with
+// the hidden state that was actually present at the start of that chunk.
+//
+// Setting `ATARI` to `true` instead trains on a pixel-observation Gym env
+// (see `atari_wrapper`): frames are preprocessed and stacked, and a
+// convolutional actor-critic is trained feed-forward (no recurrence) with
+// the same GAE/PPO machinery.
 //
 // This is adapted from OpenAI Spinning Up series:
 // https://spinningup.openai.com/en/latest/spinningup/rl_intro3.html
@@ -10,109 +43,358 @@
 extern crate cpython;
 extern crate tch;
 
-use cpython::{NoArgs, ObjectProtocol, PyObject, PyResult, Python};
-use tch::nn::OptimizerConfig;
+mod atari_wrapper;
+mod vec_gym_env;
+mod vec_normalize;
+
+use atari_wrapper::{nn_cnn, AtariVecEnv, NSTACK};
+use cpython::{PyResult, Python};
+use std::path::Path;
+use tch::nn::{OptimizerConfig, RNN};
 use tch::{nn, Tensor};
+use vec_gym_env::{ActionSpace, VecGymEnv};
+use vec_normalize::VecNormalize;
+
+const NPROCS: i64 = 16;
+const NSTEPS: i64 = 200;
+const GAMMA: f64 = 0.99;
+const LAMBDA: f64 = 0.95;
+const ENTROPY_COEF: f64 = 0.01;
+const CLIP_EPS: f64 = 0.2;
+const PPO_EPOCHS: i64 = 4;
+const MINIBATCH_SIZE: i64 = 256;
+const HIDDEN_SIZE: i64 = 32;
+const CHUNK_LEN: i64 = 128;
+const CHECKPOINT_DIR: &str = "ppo-checkpoint";
+
+const ATARI: bool = false;
+const ATARI_ENV_ID: &str = "BreakoutNoFrameskip-v4";
+const VECTOR_ENV_ID: &str = "CartPole-v0";
+
+struct RecurrentActorCritic {
+    lstm: nn::LSTM,
+    actor: nn::Linear,
+    critic: nn::Linear,
+}
 
-fn model(p: &nn::Path) -> impl nn::Module {
-    nn::Sequential::new()
-        .add(nn::Linear::new(p / "lin1", 4, 32, Default::default()))
-        .add_fn(|xs| xs.tanh())
-        .add(nn::Linear::new(p / "lin2", 32, 2, Default::default()))
+impl RecurrentActorCritic {
+    fn new(p: &nn::Path, nobs: i64, nact: i64, hidden_size: i64) -> RecurrentActorCritic {
+        RecurrentActorCritic {
+            lstm: nn::LSTM::new(p / "lstm", nobs, hidden_size, Default::default()),
+            actor: nn::Linear::new(p / "actor", hidden_size, nact, Default::default()),
+            critic: nn::Linear::new(p / "critic", hidden_size, 1, Default::default()),
+        }
+    }
+
+    fn zero_state(&self, batch_dim: i64) -> nn::LSTMState {
+        self.lstm.zero_state(batch_dim)
+    }
+
+    // Advances the recurrent state by a single timestep for a batch of
+    // observations, returning the (action logits, state value, new hidden
+    // state) triple.
+    fn step(&self, obs: &Tensor, state: &nn::LSTMState) -> (Tensor, Tensor, nn::LSTMState) {
+        let state = self.lstm.step(obs, state);
+        // `state.0` is `[num_layers, batch, hidden]`; drop the (size-1)
+        // layer dimension so the actor/critic heads see a plain `[batch,
+        // hidden]` input and their outputs keep `batch` as dimension 0.
+        let h = state.0.squeeze1(0);
+        (h.apply(&self.actor), h.apply(&self.critic), state)
+    }
+
+    // Replays a chunk of consecutive observations through the LSTM one
+    // timestep at a time, seeded with the hidden state at the start of the
+    // chunk, so that gradients flow through the recurrence over the whole
+    // chunk (truncated BPTT).
+    fn replay(&self, chunk_obs: &[Tensor], init_state: &nn::LSTMState) -> (Tensor, Tensor) {
+        let mut state = nn::LSTMState(init_state.0.shallow_clone(), init_state.1.shallow_clone());
+        let mut logits = Vec::with_capacity(chunk_obs.len());
+        let mut values = Vec::with_capacity(chunk_obs.len());
+        for obs in chunk_obs {
+            let (step_logits, step_value, new_state) = self.step(obs, &state);
+            logits.push(step_logits);
+            values.push(step_value);
+            state = new_state;
+        }
+        (Tensor::cat(&logits, 0), Tensor::cat(&values, 0))
+    }
 }
 
 #[derive(Debug)]
 struct Step {
     obs: Tensor,
     action: i64,
+    // log-probability of `action` under the policy that generated it, used
+    // by the clipped surrogate objective below.
+    action_logp: f64,
     reward: f64,
     is_done: bool,
+    // Hidden state the LSTM was in just before this step was taken, so a
+    // replayed chunk starting here can be seeded correctly.
+    hidden_h: Tensor,
+    hidden_c: Tensor,
 }
 
-impl Step {
-    fn copy_with_obs(&self, obs: &Tensor) -> Step {
-        Step {
-            obs: obs.copy(),
-            action: self.action,
-            reward: self.reward,
-            is_done: self.is_done,
-        }
+// Computes the GAE advantage at every step of a single trajectory, walking
+// backward so that the running sum naturally resets at episode boundaries
+// (the bootstrap and decay terms are masked out wherever `is_done` holds).
+fn gae(
+    rewards: &[f64],
+    values: &[f64],
+    is_done: &[bool],
+    last_value: f64,
+    gamma: f64,
+    lambda: f64,
+) -> Vec<f64> {
+    let mut advantages = vec![0f64; rewards.len()];
+    let mut acc_advantage = 0f64;
+    let mut next_value = last_value;
+    for t in (0..rewards.len()).rev() {
+        let mask = if is_done[t] { 0.0 } else { 1.0 };
+        let delta = rewards[t] + gamma * next_value * mask - values[t];
+        acc_advantage = delta + gamma * lambda * mask * acc_advantage;
+        advantages[t] = acc_advantage;
+        next_value = values[t];
     }
+    advantages
 }
 
-fn accumulate_rewards(steps: &[Step]) -> Vec<f64> {
-    let mut rewards: Vec<f64> = steps.iter().map(|s| s.reward).collect();
-    let mut acc_reward = 0f64;
-    for (i, reward) in rewards.iter_mut().enumerate().rev() {
-        if steps[i].is_done {
-            acc_reward = 0.0;
+// Trains the recurrent vector-observation policy described at the top of
+// this file (CartPole and similar low-dimensional control tasks).
+fn train_recurrent(gil: &cpython::GILGuard) -> PyResult<()> {
+    let mut env = VecNormalize::new(VecGymEnv::new(gil, VECTOR_ENV_ID, NPROCS)?, GAMMA);
+    let nobs = env.observation_space()[0];
+    let nact = match env.action_space() {
+        ActionSpace::Discrete(n) => *n,
+        ActionSpace::Continuous { shape, low, high } => panic!(
+            "this example only supports discrete action spaces, got a continuous one with shape {:?} (low {:?}, high {:?})",
+            shape, low, high
+        ),
+    };
+
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let model = RecurrentActorCritic::new(&vs.root(), nobs, nact, HIDDEN_SIZE);
+    let opt = nn::Adam::default().build(&vs, 1e-2).unwrap();
+
+    for epoch_idx in 0..50 {
+        let mut obs = env.reset()?;
+        let mut hidden = model.zero_state(NPROCS);
+        let mut trajectories: Vec<Vec<Step>> = (0..NPROCS).map(|_| vec![]).collect();
+        // Perform some rollouts with the current model, NPROCS environments at a time.
+        for _ in 0..NSTEPS {
+            let (actions, action_logp, new_hidden) = tch::no_grad(|| {
+                let (logits, _value, new_hidden) = model.step(&obs, &hidden);
+                let actions = logits.softmax(1).multinomial(1, true);
+                let action_logp = logits
+                    .log_softmax(1)
+                    .gather(1, &actions, false)
+                    .squeeze1(1);
+                (actions, action_logp, new_hidden)
+            });
+            let (next_obs, rewards, is_done) = env.step(&actions.squeeze1(1))?;
+            for i in 0..NPROCS {
+                trajectories[i as usize].push(Step {
+                    obs: obs.get(i).copy(),
+                    action: i64::from(actions.get(i)),
+                    action_logp: f64::from(action_logp.get(i)),
+                    reward: f64::from(rewards.get(i)),
+                    is_done: f64::from(is_done.get(i)) > 0.5,
+                    // `hidden` is `[num_layers, NPROCS, hidden]`; select
+                    // along the batch dimension (dim 1), not the
+                    // (size-1) layer dimension, to pull out this env's
+                    // per-layer state.
+                    hidden_h: hidden.0.select(1, i).copy(),
+                    hidden_c: hidden.1.select(1, i).copy(),
+                });
+            }
+            // Episodes that just ended start the next timestep with a fresh
+            // hidden state; the rest carry theirs forward.
+            let not_done = (Tensor::from(1.) - &is_done).unsqueeze(1);
+            hidden = nn::LSTMState((&new_hidden.0 * &not_done), (&new_hidden.1 * &not_done));
+            obs = next_obs;
         }
-        acc_reward += *reward;
-        *reward = acc_reward;
-    }
-    rewards
-}
+        let sum_r: f64 = trajectories.iter().flatten().map(|s| s.reward).sum();
+        let episodes: i64 = trajectories.iter().flatten().map(|s| s.is_done as i64).sum();
+        println!(
+            "epoch: {:<3} episodes: {:<5} avg reward per episode: {:.2}",
+            epoch_idx,
+            episodes,
+            sum_r / episodes as f64
+        );
 
-struct GymEnv<'a> {
-    py: Python<'a>,
-    env: PyObject,
-}
+        // Estimate the value of every visited state (and of the state the
+        // rollout ended on, for bootstrapping) under the current critic, then
+        // turn those into per-trajectory GAE advantages and bootstrapped
+        // returns. Values are recomputed by replaying each trajectory
+        // through the LSTM from its starting hidden state.
+        let (_, final_values, _) = tch::no_grad(|| model.step(&obs, &hidden));
+        let final_values = Vec::<f64>::from(&final_values.squeeze1(1));
+        let mut values = Vec::with_capacity((NPROCS * NSTEPS) as usize);
+        for traj in trajectories.iter() {
+            // `hidden_h`/`hidden_c` are `[num_layers, hidden]`; reinsert
+            // the batch dimension at position 1 to get the `[num_layers,
+            // 1, hidden]` a single-env replay expects.
+            let init_state = nn::LSTMState(
+                traj[0].hidden_h.unsqueeze(1).shallow_clone(),
+                traj[0].hidden_c.unsqueeze(1).shallow_clone(),
+            );
+            let chunk_obs: Vec<Tensor> = traj.iter().map(|s| s.obs.unsqueeze(0)).collect();
+            let (_, traj_values) = tch::no_grad(|| model.replay(&chunk_obs, &init_state));
+            values.extend(Vec::<f64>::from(&traj_values.squeeze1(1)));
+        }
 
-impl<'a> GymEnv<'a> {
-    fn new(gil: &cpython::GILGuard) -> PyResult<GymEnv> {
-        let py = gil.python();
-        let gym = py.import("gym")?;
-        let env = gym.call(py, "make", ("CartPole-v0",), None)?;
-        let _ = env.call_method(py, "seed", (42,), None)?;
-        Ok(GymEnv { py, env })
-    }
+        let mut advantages = Vec::with_capacity(values.len());
+        let mut returns = Vec::with_capacity(values.len());
+        for (i, traj) in trajectories.iter().enumerate() {
+            let traj_rewards: Vec<f64> = traj.iter().map(|s| s.reward).collect();
+            let traj_is_done: Vec<bool> = traj.iter().map(|s| s.is_done).collect();
+            let traj_values = &values[i * NSTEPS as usize..(i + 1) * NSTEPS as usize];
+            let traj_advantages = gae(
+                &traj_rewards,
+                traj_values,
+                &traj_is_done,
+                final_values[i],
+                GAMMA,
+                LAMBDA,
+            );
+            for (t, advantage) in traj_advantages.into_iter().enumerate() {
+                returns.push(advantage + traj_values[t]);
+                advantages.push(advantage);
+            }
+        }
+        let advantages = Tensor::float_vec(&advantages);
+        let returns = Tensor::float_vec(&returns);
+        let actions: Vec<i64> = trajectories.iter().flatten().map(|s| s.action).collect();
+        let actions = Tensor::int_vec(&actions).unsqueeze(1);
+        let logp_old: Vec<f64> = trajectories
+            .iter()
+            .flatten()
+            .map(|s| s.action_logp)
+            .collect();
+        let logp_old = Tensor::float_vec(&logp_old);
 
-    fn reset(&self) -> PyResult<Tensor> {
-        let obs = self.env.call_method(self.py, "reset", NoArgs, None)?;
-        Ok(Tensor::float_vec(&obs.extract::<Vec<f64>>(self.py)?))
-    }
+        // Run PPO_EPOCHS passes over the rollout, replaying every process's
+        // trajectory through the LSTM one `CHUNK_LEN`-step chunk at a time so
+        // the recurrence gets truncated backprop-through-time gradients.
+        for _ in 0..PPO_EPOCHS {
+            let mut chunk_start = 0;
+            while chunk_start < NSTEPS {
+                let chunk_len = CHUNK_LEN.min(NSTEPS - chunk_start) as usize;
+                let start = chunk_start as usize;
 
-    fn step(&self, action: i64) -> PyResult<Step> {
-        let py = self.py;
-        let step = self.env.call_method(py, "step", (action,), None)?;
-        Ok(Step {
-            obs: Tensor::float_vec(&step.get_item(py, 0)?.extract::<Vec<f64>>(py)?),
-            reward: step.get_item(py, 1)?.extract(py)?,
-            is_done: step.get_item(py, 2)?.extract(py)?,
-            action,
-        })
+                let mut logits = Vec::with_capacity(NPROCS as usize);
+                let mut values = Vec::with_capacity(NPROCS as usize);
+                for traj in trajectories.iter() {
+                    let init_state = nn::LSTMState(
+                        traj[start].hidden_h.unsqueeze(1).shallow_clone(),
+                        traj[start].hidden_c.unsqueeze(1).shallow_clone(),
+                    );
+                    let chunk_obs: Vec<Tensor> = traj[start..start + chunk_len]
+                        .iter()
+                        .map(|s| s.obs.unsqueeze(0))
+                        .collect();
+                    let (chunk_logits, chunk_values) = model.replay(&chunk_obs, &init_state);
+                    logits.push(chunk_logits);
+                    values.push(chunk_values);
+                }
+                let logits = Tensor::cat(&logits, 0);
+                let values = Tensor::cat(&values, 0).squeeze1(1);
+
+                let index: Vec<i64> = (0..NPROCS)
+                    .flat_map(|i| {
+                        (0..chunk_len as i64).map(move |t| i * NSTEPS + chunk_start + t)
+                    })
+                    .collect();
+                let index = Tensor::int_vec(&index);
+                let mb_actions = actions.index_select(0, &index);
+                let mb_action_mask = Tensor::zeros(&[index.size()[0], nact], tch::kind::FLOAT_CPU)
+                    .scatter_(1, &mb_actions, &Tensor::from(1.));
+                let mb_advantages = advantages.index_select(0, &index);
+                let mb_returns = returns.index_select(0, &index);
+                let mb_logp_old = logp_old.index_select(0, &index);
+
+                let log_probs = logits.log_softmax(1);
+                let action_logp = (&mb_action_mask * &log_probs).sum2(&[1], false);
+                let ratio = (action_logp - mb_logp_old).exp();
+                let clamped_ratio = ratio.clamp(1. - CLIP_EPS, 1. + CLIP_EPS);
+                let policy_loss = -(&ratio * &mb_advantages)
+                    .min1(&(clamped_ratio * &mb_advantages))
+                    .mean();
+                let entropy = -(log_probs.exp() * &log_probs).sum2(&[1], false).mean();
+                let value_loss = (values - mb_returns).pow(2).mean();
+                let loss = policy_loss + value_loss - ENTROPY_COEF * entropy;
+                opt.backward_step(&loss);
+
+                chunk_start += CHUNK_LEN;
+            }
+        }
     }
+
+    // Save the policy weights alongside the observation/reward normalization
+    // statistics, so a later evaluation run can load both and see the same
+    // normalization the policy was trained under. `env.save` creates
+    // `CHECKPOINT_DIR`, so it runs first.
+    env.save(CHECKPOINT_DIR)
+        .expect("failed to save normalization stats");
+    vs.save(Path::new(CHECKPOINT_DIR).join("vars.ot"))
+        .expect("failed to save var store");
+    Ok(())
 }
 
-fn main() -> PyResult<()> {
+#[derive(Debug)]
+struct CnnStep {
+    obs: Tensor,
+    action: i64,
+    action_logp: f64,
+    reward: f64,
+    is_done: bool,
+}
+
+// Trains the convolutional, feed-forward policy on a pixel-observation Gym
+// env such as Breakout or Pong. There is no hidden state to carry across
+// timesteps, so the rollout and PPO update are a direct, non-recurrent
+// analogue of `train_recurrent`.
+fn train_cnn(gil: &cpython::GILGuard) -> PyResult<()> {
+    let mut env = AtariVecEnv::new(VecGymEnv::new(gil, ATARI_ENV_ID, NPROCS)?);
+    let nact = match env.action_space() {
+        ActionSpace::Discrete(n) => *n,
+        ActionSpace::Continuous { shape, low, high } => panic!(
+            "this example only supports discrete action spaces, got a continuous one with shape {:?} (low {:?}, high {:?})",
+            shape, low, high
+        ),
+    };
+
     let vs = nn::VarStore::new(tch::Device::Cpu);
-    let model = model(&vs.root());
+    let model = nn_cnn(&vs.root(), NSTACK, nact);
     let opt = nn::Adam::default().build(&vs, 1e-2).unwrap();
 
-    let gil = Python::acquire_gil();
-    let env = GymEnv::new(&gil)?;
-
     for epoch_idx in 0..50 {
         let mut obs = env.reset()?;
-        let mut steps: Vec<Step> = vec![];
-        // Perform some rollouts with the current model.
-        loop {
-            let action = tch::no_grad(|| {
-                obs.unsqueeze(0)
-                    .apply(&model)
-                    .softmax(1)
-                    .multinomial(1, true)
+        let mut trajectories: Vec<Vec<CnnStep>> = (0..NPROCS).map(|_| vec![]).collect();
+        for _ in 0..NSTEPS {
+            let (actions, action_logp) = tch::no_grad(|| {
+                let (logits, _value) = model.forward(&obs);
+                let actions = logits.softmax(1).multinomial(1, true);
+                let action_logp = logits
+                    .log_softmax(1)
+                    .gather(1, &actions, false)
+                    .squeeze1(1);
+                (actions, action_logp)
             });
-            let action = i64::from(action);
-            let step = env.step(action)?;
-            steps.push(step.copy_with_obs(&obs));
-            obs = if step.is_done { env.reset()? } else { step.obs };
-            if step.is_done && steps.len() > 5000 {
-                break;
+            let (next_obs, rewards, is_done) = env.step(&actions.squeeze1(1))?;
+            for i in 0..NPROCS {
+                trajectories[i as usize].push(CnnStep {
+                    obs: obs.get(i).copy(),
+                    action: i64::from(actions.get(i)),
+                    action_logp: f64::from(action_logp.get(i)),
+                    reward: f64::from(rewards.get(i)),
+                    is_done: f64::from(is_done.get(i)) > 0.5,
+                });
             }
+            obs = next_obs;
         }
-        let sum_r: f64 = steps.iter().map(|s| s.reward).sum();
-        let episodes: i64 = steps.iter().map(|s| s.is_done as i64).sum();
+        let sum_r: f64 = trajectories.iter().flatten().map(|s| s.reward).sum();
+        let episodes: i64 = trajectories.iter().flatten().map(|s| s.is_done as i64).sum();
         println!(
             "epoch: {:<3} episodes: {:<5} avg reward per episode: {:.2}",
             epoch_idx,
@@ -120,22 +402,92 @@ fn main() -> PyResult<()> {
             sum_r / episodes as f64
         );
 
-        // Train the model via policy gradient on the rollout data.
+        let (_, final_values) = tch::no_grad(|| model.forward(&obs));
+        let final_values = Vec::<f64>::from(&final_values.squeeze1(1));
+        let all_obs: Vec<Tensor> = trajectories
+            .iter()
+            .flatten()
+            .map(|s| s.obs.shallow_clone())
+            .collect();
+        let (_, values) = tch::no_grad(|| model.forward(&Tensor::stack(&all_obs, 0)));
+        let values = Vec::<f64>::from(&values.squeeze1(1));
+
+        let mut advantages = Vec::with_capacity(values.len());
+        let mut returns = Vec::with_capacity(values.len());
+        for (i, traj) in trajectories.iter().enumerate() {
+            let traj_rewards: Vec<f64> = traj.iter().map(|s| s.reward).collect();
+            let traj_is_done: Vec<bool> = traj.iter().map(|s| s.is_done).collect();
+            let traj_values = &values[i * NSTEPS as usize..(i + 1) * NSTEPS as usize];
+            let traj_advantages = gae(
+                &traj_rewards,
+                traj_values,
+                &traj_is_done,
+                final_values[i],
+                GAMMA,
+                LAMBDA,
+            );
+            for (t, advantage) in traj_advantages.into_iter().enumerate() {
+                returns.push(advantage + traj_values[t]);
+                advantages.push(advantage);
+            }
+        }
+
+        let steps: Vec<CnnStep> = trajectories.into_iter().flatten().collect();
         let batch_size = steps.len() as i64;
         let actions: Vec<i64> = steps.iter().map(|s| s.action).collect();
         let actions = Tensor::int_vec(&actions).unsqueeze(1);
-        let rewards = accumulate_rewards(&steps);
-        let rewards = Tensor::float_vec(&rewards);
-        let action_mask = Tensor::zeros(&[batch_size, 2], tch::kind::FLOAT_CPU).scatter_(
+        let action_mask = Tensor::zeros(&[batch_size, nact], tch::kind::FLOAT_CPU).scatter_(
             1,
             &actions,
             &Tensor::from(1.),
         );
+        let logp_old = Tensor::float_vec(&steps.iter().map(|s| s.action_logp).collect::<Vec<_>>());
+        let advantages = Tensor::float_vec(&advantages);
+        let returns = Tensor::float_vec(&returns);
         let obs: Vec<Tensor> = steps.into_iter().map(|s| s.obs).collect();
-        let logits = Tensor::stack(&obs, 0).apply(&model);
-        let log_probs = (action_mask * logits.log_softmax(1)).sum2(&[1], false);
-        let loss = -(rewards * log_probs).mean();
-        opt.backward_step(&loss)
+        let obs = Tensor::stack(&obs, 0);
+
+        for _ in 0..PPO_EPOCHS {
+            let batch_indexes = Tensor::randperm(batch_size, tch::kind::INT64_CPU);
+            let mut start = 0;
+            while start < batch_size {
+                let size = MINIBATCH_SIZE.min(batch_size - start);
+                let index = batch_indexes.narrow(0, start, size);
+                let mb_obs = obs.index_select(0, &index);
+                let mb_action_mask = action_mask.index_select(0, &index);
+                let mb_advantages = advantages.index_select(0, &index);
+                let mb_returns = returns.index_select(0, &index);
+                let mb_logp_old = logp_old.index_select(0, &index);
+
+                let (logits, values) = model.forward(&mb_obs);
+                let values = values.squeeze1(1);
+                let log_probs = logits.log_softmax(1);
+                let action_logp = (&mb_action_mask * &log_probs).sum2(&[1], false);
+                let ratio = (action_logp - mb_logp_old).exp();
+                let clamped_ratio = ratio.clamp(1. - CLIP_EPS, 1. + CLIP_EPS);
+                let policy_loss = -(&ratio * &mb_advantages)
+                    .min1(&(clamped_ratio * &mb_advantages))
+                    .mean();
+                let entropy = -(log_probs.exp() * &log_probs).sum2(&[1], false).mean();
+                let value_loss = (values - mb_returns).pow(2).mean();
+                let loss = policy_loss + value_loss - ENTROPY_COEF * entropy;
+                opt.backward_step(&loss);
+
+                start += size;
+            }
+        }
     }
+
+    vs.save(Path::new(CHECKPOINT_DIR).join("vars-cnn.ot"))
+        .expect("failed to save var store");
     Ok(())
 }
+
+fn main() -> PyResult<()> {
+    let gil = Python::acquire_gil();
+    if ATARI {
+        train_cnn(&gil)
+    } else {
+        train_recurrent(&gil)
+    }
+}
@@ -0,0 +1,133 @@
+// A thin wrapper around `nprocesses` Python `gym.Env` instances, used by the
+// reinforcement learning examples in this directory.
+//
+// `VecGymEnv` drives `nprocesses` independent instances of the same
+// environment within this process and steps them together, so a single
+// `apply(&model)` call can produce actions for all of them at once.
+use cpython::{NoArgs, ObjectProtocol, PyObject, PyResult, Python};
+use tch::Tensor;
+
+/// The action space of a Gym environment, discovered at runtime from the
+/// Python `env.action_space` object.
+#[derive(Debug)]
+pub enum ActionSpace {
+    Discrete(i64),
+    Continuous {
+        shape: Vec<i64>,
+        low: Tensor,
+        high: Tensor,
+    },
+}
+
+// Converts a Python observation into a flat `Vec<f64>`. Gym's `Box`
+// observations are numpy arrays, possibly multi-dimensional (e.g. the
+// `[210, 160, 3]` uint8 frame of an Atari env), so `ravel` them before
+// pulling the values out - for an already 1-D observation this is a no-op.
+fn obs_to_vec(py: Python, obs: &PyObject) -> PyResult<Vec<f64>> {
+    obs.call_method(py, "ravel", NoArgs, None)?
+        .call_method(py, "tolist", NoArgs, None)?
+        .extract::<Vec<f64>>(py)
+}
+
+fn read_action_space(py: Python, action_space: &PyObject) -> PyResult<ActionSpace> {
+    let type_name = action_space.get_type(py).name(py).into_owned();
+    let action_space = match type_name.as_str() {
+        "Discrete" => ActionSpace::Discrete(action_space.getattr(py, "n")?.extract(py)?),
+        _ => ActionSpace::Continuous {
+            shape: action_space.getattr(py, "shape")?.extract::<Vec<i64>>(py)?,
+            low: Tensor::float_vec(&action_space.getattr(py, "low")?.extract::<Vec<f64>>(py)?),
+            high: Tensor::float_vec(&action_space.getattr(py, "high")?.extract::<Vec<f64>>(py)?),
+        },
+    };
+    Ok(action_space)
+}
+
+/// A batch of `nprocesses` independent copies of the same Gym environment.
+pub struct VecGymEnv<'a> {
+    py: Python<'a>,
+    envs: Vec<PyObject>,
+    observation_space: Vec<i64>,
+    action_space: ActionSpace,
+}
+
+impl<'a> VecGymEnv<'a> {
+    pub fn new(gil: &cpython::GILGuard, env_id: &str, nprocesses: i64) -> PyResult<VecGymEnv> {
+        let py = gil.python();
+        let gym = py.import("gym")?;
+        let mut envs = Vec::with_capacity(nprocesses as usize);
+        for i in 0..nprocesses {
+            let env = gym.call(py, "make", (env_id,), None)?;
+            let _ = env.call_method(py, "seed", (42 + i,), None)?;
+            envs.push(env);
+        }
+        let observation_space = envs[0]
+            .getattr(py, "observation_space")?
+            .getattr(py, "shape")?
+            .extract::<Vec<i64>>(py)?;
+        let action_space = read_action_space(py, &envs[0].getattr(py, "action_space")?)?;
+        Ok(VecGymEnv {
+            py,
+            envs,
+            observation_space,
+            action_space,
+        })
+    }
+
+    pub fn nprocesses(&self) -> i64 {
+        self.envs.len() as i64
+    }
+
+    pub fn observation_space(&self) -> &[i64] {
+        &self.observation_space
+    }
+
+    pub fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    /// Resets every sub-environment and returns a `[nprocesses, ..obs_shape]`
+    /// tensor of the initial observations.
+    pub fn reset(&self) -> PyResult<Tensor> {
+        let mut obs = Vec::with_capacity(self.envs.len());
+        for env in self.envs.iter() {
+            let o = env.call_method(self.py, "reset", NoArgs, None)?;
+            obs.push(Tensor::float_vec(&obs_to_vec(self.py, &o)?));
+        }
+        Ok(Tensor::stack(&obs, 0))
+    }
+
+    /// Steps every sub-environment with the corresponding entry of `actions`
+    /// (a `[nprocesses]` int tensor), returning stacked next-observations,
+    /// rewards and done flags, each with a leading `nprocesses` dimension.
+    /// A sub-environment that reports `done` is reset immediately, and the
+    /// observation returned for that slot on this call is the fresh
+    /// post-reset observation (not the terminal one), so that the
+    /// `(obs, action)` pair the caller records for the *next* timestep
+    /// actually matches the state the environment stepped from. `is_done`
+    /// already tells GAE where to stop bootstrapping across the boundary.
+    pub fn step(&self, actions: &Tensor) -> PyResult<(Tensor, Tensor, Tensor)> {
+        let mut obs = Vec::with_capacity(self.envs.len());
+        let mut rewards = Vec::with_capacity(self.envs.len());
+        let mut is_done = Vec::with_capacity(self.envs.len());
+        for (i, env) in self.envs.iter().enumerate() {
+            let action = i64::from(actions.get(i as i64));
+            let step = env.call_method(self.py, "step", (action,), None)?;
+            let reward: f64 = step.get_item(self.py, 1)?.extract(self.py)?;
+            let done: bool = step.get_item(self.py, 2)?.extract(self.py)?;
+            let next_obs = if done {
+                let reset_obs = env.call_method(self.py, "reset", NoArgs, None)?;
+                Tensor::float_vec(&obs_to_vec(self.py, &reset_obs)?)
+            } else {
+                Tensor::float_vec(&obs_to_vec(self.py, &step.get_item(self.py, 0)?)?)
+            };
+            obs.push(next_obs);
+            rewards.push(reward);
+            is_done.push(if done { 1f64 } else { 0f64 });
+        }
+        Ok((
+            Tensor::stack(&obs, 0),
+            Tensor::float_vec(&rewards),
+            Tensor::float_vec(&is_done),
+        ))
+    }
+}
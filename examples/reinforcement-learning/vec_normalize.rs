@@ -0,0 +1,196 @@
+// Running mean/variance normalization for observations and rewards, in the
+// style of the `VecNormalize` wrapper from OpenAI Baselines.
+//
+// CartPole (and most Gym envs) mix observation dimensions with very
+// different scales, and raw returns can vary by orders of magnitude across
+// environments; feeding either straight into the network destabilizes
+// training. `VecNormalize` tracks running statistics with Welford's
+// parallel-update algorithm and rescales observations/rewards on the fly.
+use crate::vec_gym_env::{ActionSpace, VecGymEnv};
+use cpython::PyResult;
+use std::path::Path;
+use tch::Tensor;
+
+/// Running per-dimension mean and variance, updated batch-by-batch with
+/// Welford's parallel-update formula (Chan et al. 1979) so the statistics
+/// can be combined without revisiting earlier batches.
+pub struct RunningMeanStd {
+    mean: Vec<f64>,
+    var: Vec<f64>,
+    count: f64,
+}
+
+impl RunningMeanStd {
+    pub fn new(dim: usize) -> RunningMeanStd {
+        RunningMeanStd {
+            mean: vec![0.; dim],
+            var: vec![1.; dim],
+            count: 1e-4,
+        }
+    }
+
+    pub fn update(&mut self, batch: &[Vec<f64>]) {
+        let n_b = batch.len() as f64;
+        if n_b == 0. {
+            return;
+        }
+        let dim = self.mean.len();
+        let mut batch_mean = vec![0f64; dim];
+        for row in batch {
+            for d in 0..dim {
+                batch_mean[d] += row[d] / n_b;
+            }
+        }
+        let mut batch_var = vec![0f64; dim];
+        for row in batch {
+            for d in 0..dim {
+                let diff = row[d] - batch_mean[d];
+                batch_var[d] += diff * diff / n_b;
+            }
+        }
+        let tot_count = self.count + n_b;
+        for d in 0..dim {
+            let delta = batch_mean[d] - self.mean[d];
+            let m2 = self.var[d] * self.count
+                + batch_var[d] * n_b
+                + delta * delta * self.count * n_b / tot_count;
+            self.mean[d] += delta * n_b / tot_count;
+            self.var[d] = m2 / tot_count;
+        }
+        self.count = tot_count;
+    }
+
+    pub fn normalize_clipped(&self, x: &[f64], clip: f64) -> Vec<f64> {
+        x.iter()
+            .zip(self.mean.iter())
+            .zip(self.var.iter())
+            .map(|((v, mean), var)| (((v - mean) / (var + 1e-8).sqrt()).max(-clip)).min(clip))
+            .collect()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let floats_line = |xs: &[f64]| {
+            xs.iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let content = format!(
+            "{}\n{}\n{}\n",
+            self.count,
+            floats_line(&self.mean),
+            floats_line(&self.var)
+        );
+        std::fs::write(path, content)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<RunningMeanStd> {
+        let content = std::fs::read_to_string(path)?;
+        let mut lines = content.lines();
+        let parse_line = |line: &str| {
+            line.split_whitespace()
+                .map(|v| v.parse().unwrap())
+                .collect()
+        };
+        let count = lines.next().unwrap().parse().unwrap();
+        let mean = parse_line(lines.next().unwrap());
+        let var = parse_line(lines.next().unwrap());
+        Ok(RunningMeanStd { mean, var, count })
+    }
+}
+
+fn to_rows(t: &Tensor, dim: usize) -> Vec<Vec<f64>> {
+    Vec::<f64>::from(t)
+        .chunks(dim)
+        .map(|row| row.to_vec())
+        .collect()
+}
+
+/// Wraps a `VecGymEnv`, normalizing observations with a running mean/std and
+/// rewards by the running standard deviation of the discounted return.
+pub struct VecNormalize<'a> {
+    env: VecGymEnv<'a>,
+    obs_rms: RunningMeanStd,
+    ret_rms: RunningMeanStd,
+    returns: Vec<f64>,
+    gamma: f64,
+}
+
+impl<'a> VecNormalize<'a> {
+    pub fn new(env: VecGymEnv<'a>, gamma: f64) -> VecNormalize<'a> {
+        let obs_dim = env.observation_space().iter().product::<i64>() as usize;
+        let nprocs = env.nprocesses() as usize;
+        VecNormalize {
+            obs_rms: RunningMeanStd::new(obs_dim),
+            ret_rms: RunningMeanStd::new(1),
+            returns: vec![0.; nprocs],
+            gamma,
+            env,
+        }
+    }
+
+    pub fn observation_space(&self) -> &[i64] {
+        self.env.observation_space()
+    }
+
+    pub fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn normalize_obs(&mut self, obs: &Tensor) -> Tensor {
+        let dim = self.obs_rms.mean.len();
+        let rows = to_rows(obs, dim);
+        self.obs_rms.update(&rows);
+        let normalized: Vec<f64> = rows
+            .iter()
+            .flat_map(|row| self.obs_rms.normalize_clipped(row, 10.))
+            .collect();
+        Tensor::float_vec(&normalized).view(obs.size().as_slice())
+    }
+
+    pub fn reset(&mut self) -> PyResult<Tensor> {
+        let obs = self.env.reset()?;
+        Ok(self.normalize_obs(&obs))
+    }
+
+    pub fn step(&mut self, actions: &Tensor) -> PyResult<(Tensor, Tensor, Tensor)> {
+        let (obs, rewards, is_done) = self.env.step(actions)?;
+        let obs = self.normalize_obs(&obs);
+
+        let raw_rewards = Vec::<f64>::from(&rewards);
+        let done = Vec::<f64>::from(&is_done);
+        let mut discounted_returns = Vec::with_capacity(raw_rewards.len());
+        for (i, reward) in raw_rewards.iter().enumerate() {
+            self.returns[i] = self.returns[i] * self.gamma + reward;
+            discounted_returns.push(vec![self.returns[i]]);
+            if done[i] > 0.5 {
+                self.returns[i] = 0.;
+            }
+        }
+        self.ret_rms.update(&discounted_returns);
+        let return_std = (self.ret_rms.var[0] + 1e-8).sqrt();
+        let scaled_rewards: Vec<f64> = raw_rewards.iter().map(|r| r / return_std).collect();
+        Ok((obs, Tensor::float_vec(&scaled_rewards), is_done))
+    }
+
+    /// Persists the running statistics to `obs.stats`/`ret.stats` under
+    /// `dir`, so that an evaluation run loading the same `VarStore` can load
+    /// these alongside it and normalize observations the same way.
+    pub fn save(&self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::create_dir_all(&dir)?;
+        self.obs_rms.save(dir.as_ref().join("obs.stats"))?;
+        self.ret_rms.save(dir.as_ref().join("ret.stats"))
+    }
+
+    /// Counterpart to `save`. Not called anywhere in this example yet -
+    /// there is no eval/inference entry point here, only training - but an
+    /// evaluation binary loading the `VarStore` checkpoint back in should
+    /// call this with the same `dir` so it normalizes observations the same
+    /// way the training run did.
+    #[allow(dead_code)]
+    pub fn load_stats(&mut self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        self.obs_rms = RunningMeanStd::load(dir.as_ref().join("obs.stats"))?;
+        self.ret_rms = RunningMeanStd::load(dir.as_ref().join("ret.stats"))?;
+        Ok(())
+    }
+}